@@ -0,0 +1,57 @@
+// Imports
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// ----------------------------- STRUCTS ----------------------------------
+
+// Genesis and consensus parameters for a network, loaded from a JSON file at startup instead of
+// baked in as constants, so differently-configured networks can coexist without a recompile.
+// Modeled on the named chain-spec files (e.g. Frontier, Morden) used by OpenEthereum
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainSpec {
+    pub chain_name: String,
+    pub version: String,
+    pub genesis_data: String,
+    pub genesis_timestamp: i64,
+    pub difficulty_zero_bits: u32,
+    pub block_topic: String,
+    // How many seconds a block is meant to take to mine, used as the target for difficulty
+    // retargeting
+    pub target_block_seconds: i64,
+    // How often (in blocks) the required difficulty is recomputed from actual block times
+    pub retarget_interval: u64,
+    pub min_difficulty_bits: u32,
+    pub max_difficulty_bits: u32,
+}
+
+// ----------------------------- IMPLEMENTATIONS ----------------------------------
+
+impl ChainSpec {
+    pub fn load(path: &Path) -> Self {
+        let contents = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("can read chain spec at {}: {}", path.display(), e));
+        serde_json::from_str(&contents).expect("can parse chain spec")
+    }
+}
+
+impl Default for ChainSpec {
+    // Used when a node is started without --chain-spec. Keeps the old "Genesis Block" data and the
+    // two-zero difficulty of DIFFICULTY_PREFIX, and adds a fixed genesis timestamp and the
+    // retargeting parameters; the genesis block built from it is not the old one (its hash is
+    // derived from these values and its timestamp is fixed)
+    fn default() -> Self {
+        Self {
+            chain_name: String::from("rust-blockchain-dev"),
+            version: String::from("1"),
+            genesis_data: String::from("Genesis Block"),
+            genesis_timestamp: 1625097600,
+            difficulty_zero_bits: 2,
+            block_topic: String::from("blockchain"),
+            target_block_seconds: 10,
+            retarget_interval: 10,
+            min_difficulty_bits: 1,
+            max_difficulty_bits: 24,
+        }
+    }
+}
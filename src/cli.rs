@@ -0,0 +1,55 @@
+// Imports
+use clap::Parser;
+use libp2p::Multiaddr;
+use std::path::PathBuf;
+
+// ----------------------------- STRUCTS ----------------------------------
+
+// Command-line configuration for a node. Lets an operator give this node a stable identity and
+// choose where it listens and stores its data, rather than relying on the hardcoded defaults a
+// quick demo run used before
+#[derive(Parser, Debug)]
+#[clap(name = "rust-blockchain", about = "A toy blockchain node over libp2p")]
+pub struct Cli {
+    // Directory this node's keyfile and SQLite store live in
+    #[clap(long, default_value = "data")]
+    pub data_dir: PathBuf,
+
+    // Multiaddr to listen on
+    #[clap(long, default_value = "/ip4/0.0.0.0/tcp/0")]
+    pub listen: Multiaddr,
+
+    // Path to this node's ed25519 keyfile. Defaults to `node.key` inside `data_dir`, generated
+    // and saved there on first run if it doesn't exist yet
+    #[clap(long)]
+    pub keyfile: Option<PathBuf>,
+
+    // Path to a ChainSpec JSON file with genesis/consensus parameters for the network to join.
+    // Without it, a node falls back to ChainSpec::default()
+    #[clap(long)]
+    pub chain_spec: Option<PathBuf>,
+
+    // Multiaddr of a rendezvous point to register with and discover peers through
+    #[clap(long)]
+    pub rendezvous: Option<Multiaddr>,
+
+    // Act as a rendezvous point for other nodes
+    #[clap(long)]
+    pub rendezvous_server: bool,
+}
+
+// ----------------------------- IMPLEMENTATIONS ----------------------------------
+
+impl Cli {
+    // Resolved keyfile path, defaulting to `node.key` inside the data dir
+    pub fn keyfile_path(&self) -> PathBuf {
+        self.keyfile
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("node.key"))
+    }
+
+    // Resolved path for the on-disk SQLite store, inside the data dir
+    pub fn store_path(&self) -> PathBuf {
+        self.data_dir.join("blockchain.db")
+    }
+}
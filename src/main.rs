@@ -3,15 +3,17 @@ use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use log::{error, warn, info};
 use sha2::{Digest, Sha256};
+use std::path::Path;
 use std::time::Duration;
+use clap::Parser;
 use libp2p::{
     core::upgrade,
     futures::StreamExt,
     mplex,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
+    swarm::{Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    Transport,
+    PeerId, Transport,
 };
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
@@ -22,8 +24,12 @@ use tokio::{
 
 
 // ----------------------------- STRUCTS ----------------------------------
-pub struct App {  // Non persistent blockchain
-    pub blockchain: Vec<Block>,  // The blockchain will be a vector of Blocks
+
+pub struct App {
+    pub blockchain: Vec<Block>,  // In-memory copy of the chain, rehydrated from `store` on startup
+    pub store: storage::Storage,  // On-disk SQLite store so the chain survives a restart
+    pub mempool: Vec<Transaction>,  // Transactions received but not yet mined into a block
+    pub chain_spec: ChainSpec,  // Genesis and consensus parameters for the network this node belongs to
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,42 +38,97 @@ pub struct Block {  // Attributes in each Block
     pub hash: String,
     pub prev_hash: String,
     pub timestamp: i64,
-    pub data: String,
-    pub nonce: u64, 
+    pub transactions: Vec<Transaction>,
+    pub merkle_root: String,
+    pub nonce: u64,
 }
 
 // ----------------------------- HELPERS ----------------------------------
 
-// When mining a block the person has to hash the data with SHA256 and find a hash in binary which starts with "00"
-// This denotes our difficulty level
-// Increasing the number of zeroes increases the difficulty
-const DIFFICULTY_PREFIX: &str = "00";
+mod chain_spec;
+mod cli;
 mod p2p;
+mod storage;
+mod transaction;
+
+use chain_spec::ChainSpec;
+use cli::Cli;
+use transaction::Transaction;
+
+// Loads genesis/consensus parameters for the network this node should join from `--chain-spec`;
+// without it, a node falls back to ChainSpec::default()
+fn load_chain_spec(path: Option<&Path>) -> ChainSpec {
+    path.map(ChainSpec::load).unwrap_or_default()
+}
 
+// Each byte is formatted with the `08` zero-padding width so a byte like `0x01` turns into
+// "00000001" rather than "1" - without the padding, leading zero bits inside a byte were silently
+// dropped and the difficulty check counted far fewer zero bits than the hash actually had
 fn hash_to_binary_representation(hash: &[u8]) -> String {
     let mut result: String = String::default();
     for c in hash {
-        result.push_str(&format!("{:b}", c));
+        result.push_str(&format!("{:08b}", c));
     }
     result
 }
 
+// Bitcoin-style difficulty retargeting: the chain starts out at the ChainSpec's
+// `difficulty_zero_bits`, and every `retarget_interval` blocks we look back at the wall-clock
+// time the previous window of blocks actually took versus the desired span
+// (retarget_interval * target_block_seconds), nudging the required zero-bits up by one if
+// mining was running more than 2x too fast, or down by one if more than 2x too slow, clamped
+// to the spec's min/max. `history` is the chain of blocks preceding `height`
+fn required_difficulty_bits(history: &[Block], height: u64, chain_spec: &ChainSpec) -> u32 {
+    let interval = chain_spec.retarget_interval;
+    let mut bits = chain_spec.difficulty_zero_bits;
+
+    // `height` is expected to equal `history.len()` (history holds exactly the blocks
+    // preceding the one at `height`). Bail out to the un-retargeted default rather than
+    // indexing into `history` if that invariant doesn't hold - callers must check block_id
+    // continuity against `history` before relying on this for an untrusted block
+    if interval == 0 || height as usize > history.len() {
+        return bits;
+    }
+
+    let mut boundary = interval;
+    while boundary <= height {
+        let window_start = &history[(boundary - interval) as usize];
+        let window_end = &history[(boundary - 1) as usize];
+        let actual_span = window_end.timestamp - window_start.timestamp;
+        let desired_span = interval as i64 * chain_spec.target_block_seconds;
+
+        if actual_span < desired_span / 2 {
+            bits += 1;
+        } else if actual_span > desired_span * 2 {
+            bits = bits.saturating_sub(1);
+        }
+        bits = bits.clamp(chain_spec.min_difficulty_bits, chain_spec.max_difficulty_bits);
+
+        boundary += interval;
+    }
+
+    bits
+}
+
 // The mining function will return a nonce and a hash
 // We can create a new block with the timestamp, given data, block id, previous hash and new hash and nonce
 // After announcing that we're about to mine a block we set the nonce to 0
 // Then start an endless loop that increments the nonce each step, and calculate the hash
-fn mine_block(block_id: u64, timestamp: i64, prev_hash: &str, data: &str) -> (u64, String) {
+// `required_bits` is the number of leading zero bits the hash must have, computed per-block by
+// `required_difficulty_bits` rather than a fixed constant, so it can retarget over time
+fn mine_block(block_id: u64, timestamp: i64, prev_hash: &str, merkle_root: &str, required_bits: u32) -> (u64, String) {
     info!("Mining block...");
     let mut nonce = 0;
+    let difficulty_prefix = "0".repeat(required_bits as usize);
 
     loop {
         if nonce % 100000 == 0 {
             info!("Trying nonce {}", nonce);
         }
 
-        let hash = calculate_hash(block_id, timestamp, prev_hash, data, nonce);
+        let hash = calculate_hash(block_id, timestamp, prev_hash, merkle_root, nonce);
         let bin_hash = hash_to_binary_representation(&hash);
-        if bin_hash.starts_with(DIFFICULTY_PREFIX) {
+        if bin_hash.starts_with(&difficulty_prefix) {
             info!("Block mined with nonce {}, hash: {}, binary hash: {}", nonce, hex::encode(&hash), bin_hash);
             return (nonce, hex::encode(hash));
         }
@@ -76,38 +137,59 @@ fn mine_block(block_id: u64, timestamp: i64, prev_hash: &str, data: &str) -> (u6
 }
 
 // Create a JSON representation of the block and pass it through the SHA256 hasher
-fn calculate_hash(block_id: u64, timestamp: i64, prev_hash: &str, data: &str, nonce: u64) -> Vec<u8> {
+// The transactions themselves are folded in via their Merkle root rather than serialized in full
+fn calculate_hash(block_id: u64, timestamp: i64, prev_hash: &str, merkle_root: &str, nonce: u64) -> Vec<u8> {
     let data = serde_json::json!({
         "block_id": block_id,
         "prev_hash": prev_hash,
-        "data": data,
+        "merkle_root": merkle_root,
         "timestamp": timestamp,
         "nonce": nonce
     });
 
     let mut hash_func = Sha256::new();
     hash_func.update(data.to_string().as_bytes());
-    hash_func.finalize().as_slice().to_owned()
+    hash_func.finalize().to_vec()
 }
 
 
 // ----------------------------- IMPLEMENTATIONS ----------------------------------
 
 impl App {
-    fn new() -> Self {  // Initialization
-        Self { blockchain: vec![] }
+    fn new(chain_spec: ChainSpec, store_path: &Path) -> Self {  // Initialization, rehydrating the chain from the on-disk store
+        let store = storage::Storage::new(store_path);
+        let blockchain = store.load_chain();
+        Self { blockchain, store, mempool: vec![], chain_spec }
     }
 
-    fn genesis(&mut self) {  // Genesis block logic
+    fn genesis(&mut self) {  // Genesis block logic, seeded from the network's ChainSpec
+        info!("Creating genesis block for chain '{}': {}", self.chain_spec.chain_name, self.chain_spec.genesis_data);
+
+        let genesis_transactions = vec![];
+        let merkle_root = transaction::merkle_root(&genesis_transactions);
+        // Fold genesis_data into the hash itself, not just a log line, so two chain-specs that
+        // differ only in genesis_data don't produce byte-identical genesis blocks
+        let hash = Sha256::digest(
+            serde_json::json!({
+                "chain_name": self.chain_spec.chain_name,
+                "genesis_data": self.chain_spec.genesis_data,
+                "timestamp": self.chain_spec.genesis_timestamp,
+            })
+            .to_string()
+            .as_bytes(),
+        );
+
         let genesis_block = Block {
             block_id: 0,
-            hash: String::from("Genesis Hash"),
+            hash: hex::encode(hash),
             prev_hash: String::from("---"),
-            timestamp: Utc::now().timestamp(),
-            data: String::from("Genesis Block"),
-            nonce: 2108,
+            timestamp: self.chain_spec.genesis_timestamp,
+            merkle_root,
+            transactions: genesis_transactions,
+            nonce: 0,
         };
 
+        self.store.insert_block(&genesis_block);
         self.blockchain.push(genesis_block); // Add genesis block to the blockchain
         // Initialize the application with an empty chain and use longest chain rule later
     }
@@ -116,7 +198,8 @@ impl App {
     fn try_add_block(&mut self, block: Block) {
         let latest_block = self.blockchain.last().expect("There is at least one block in the chain");
 
-        if self.is_block_valid(&block, latest_block) {
+        if self.is_block_valid(&block, latest_block, &self.blockchain) {
+            self.store.insert_block(&block);
             self.blockchain.push(block);
         } else {
             error!("Block is not valid, not added to chain");
@@ -125,26 +208,40 @@ impl App {
 
     // Function to check if a block is valid by checking all the validity cases
     // 1. The previous hash needs to match the last block in the chain's hash
-    // 2. The hash needs to start with "00" -> DIFFICULTY_PREFIX to indicate it was mined correctly
-    // 3. The block_id needs to be the latest ID incremented by 1
-    // 4. The hash itself needs to be correct, hashing the data of the block should give the block hash
-    fn is_block_valid(&self, block: &Block, prev_block: &Block) -> bool {
+    // 2. The block_id needs to be the latest ID incremented by 1
+    // 3. The hash needs to meet the height's retargeted difficulty to indicate it was mined correctly
+    // 4. The transactions must hash to the Merkle root carried by the block
+    // 5. The hash itself needs to be correct, hashing the merkle root of the block should give the block hash
+    // `history` is the chain of blocks preceding `block`, used to recompute the difficulty this
+    // block's height was required to meet
+    fn is_block_valid(&self, block: &Block, prev_block: &Block, history: &[Block]) -> bool {
+        // Continuity is checked before anything that indexes `history` by height, since
+        // `block` comes straight off the wire (gossiped or from a ChainResponse) and an
+        // out-of-range block_id must not reach required_difficulty_bits
         if block.prev_hash != prev_block.hash {
             warn!("Block with id {} has the wrong previous hash reference", block.block_id);
             return false;
-        } else if !hash_to_binary_representation(
+        } else if block.block_id != prev_block.block_id + 1 {
+            warn!("Block with id {} is not the next block. The latest is {}", block.block_id, prev_block.block_id);
+            return false;
+        }
+
+        let required_bits = required_difficulty_bits(history, block.block_id, &self.chain_spec);
+        let difficulty_prefix = "0".repeat(required_bits as usize);
+
+        if !hash_to_binary_representation(
             &hex::decode(&block.hash).expect("Can't decode from Hex")
-        ).starts_with(DIFFICULTY_PREFIX) {
+        ).starts_with(&difficulty_prefix) {
             warn!("Block with id {} has invalid difficulty", block.block_id);
             return false;
-        } else if block.block_id != prev_block.block_id + 1 {
-            warn!("Block with id {} is not the next block. The latest is {}", block.block_id, prev_block.block_id);
+        } else if transaction::merkle_root(&block.transactions) != block.merkle_root {
+            warn!("Block with id {} has a merkle root that doesn't match its transactions", block.block_id);
             return false;
         } else if hex::encode(calculate_hash(
             block.block_id,
             block.timestamp,
             &block.prev_hash,
-            &block.data,
+            &block.merkle_root,
             block.nonce
         )) != block.hash {
             warn!("Block with id {} has invalid hash", block.block_id);
@@ -164,7 +261,7 @@ impl App {
 
             let first = chain.get(i - 1).expect("It has to exist");
             let second = chain.get(i).expect("It has to exist");
-            if !self.is_block_valid(second, first) {
+            if !self.is_block_valid(second, first, &chain[..i]) {
                 return false;
             }
         }
@@ -195,15 +292,20 @@ impl App {
 
 // The mining scheme will be implemented in Block
 impl Block {
-    pub fn new(block_id: u64, prev_hash: String, data: String) -> Self {
+    // `history` is the chain of blocks preceding this one, used to retarget the difficulty
+    // this block's height is required to meet
+    pub fn new(block_id: u64, prev_hash: String, transactions: Vec<Transaction>, chain_spec: &ChainSpec, history: &[Block]) -> Self {
         let now = Utc::now();
-        let (nonce, hash) = mine_block(block_id, now.timestamp(), &prev_hash, &data);
+        let merkle_root = transaction::merkle_root(&transactions);
+        let required_bits = required_difficulty_bits(history, block_id, chain_spec);
+        let (nonce, hash) = mine_block(block_id, now.timestamp(), &prev_hash, &merkle_root, required_bits);
         Self {
             block_id,
             hash,
             timestamp: now.timestamp(),
             prev_hash,
-            data,
+            transactions,
+            merkle_root,
             nonce
         }
     }
@@ -224,12 +326,24 @@ We then ask another node for their current blockchain to get us up to speed
 async fn main() {
     pretty_env_logger::init();
 
-    info!("Peer Id: {}", p2p::PEER_ID.clone());
-    let (response_sender, mut response_receiver) = mpsc::unbounded_channel();
+    let cli = Cli::parse();
+    std::fs::create_dir_all(&cli.data_dir).expect("can create data dir");
+
+    let keypair = p2p::load_or_generate_keypair(&cli.keyfile_path());
+    let peer_id = PeerId::from(keypair.public());
+    p2p::KEYS
+        .set(keypair)
+        .unwrap_or_else(|_| panic!("keys set once at startup"));
+    p2p::PEER_ID.set(peer_id).expect("peer id set once at startup");
+
+    info!("Peer Id: {}", p2p::peer_id());
     let (init_sender, mut init_receiver) = mpsc::unbounded_channel();
 
+    let rendezvous_addr = cli.rendezvous.clone();
+    let rendezvous_peer_id = rendezvous_addr.as_ref().and_then(p2p::extract_peer_id);
+
     let auth_keys = Keypair::<X25519Spec>::new()
-    .into_authentic(&p2p::KEYS).expect("Can create auth keys"); // Generate a new keypair
+    .into_authentic(p2p::keys()).expect("Can create auth keys"); // Derive auth keys from our stable node identity
 
     let transp = TokioTcpConfig::new()
     .upgrade(upgrade::Version::V1)
@@ -237,18 +351,26 @@ async fn main() {
     .multiplex(mplex::MplexConfig::new())
     .boxed();
 
-    let behaviour = p2p::AppBehaviour::new(App::new(), response_sender, init_sender.clone()).await;
+    let chain_spec = load_chain_spec(cli.chain_spec.as_deref());
+    let behaviour = p2p::AppBehaviour::new(
+        App::new(chain_spec, &cli.store_path()),
+        rendezvous_addr.is_some(),
+        cli.rendezvous_server,
+    ).await;
 
-    let mut swarm = SwarmBuilder::new(transp, behaviour, *p2p::PEER_ID)
+    let mut swarm = SwarmBuilder::new(transp, behaviour, p2p::peer_id())
     .executor(Box::new(|fut| {
         spawn(fut);
     })).build();
 
     let mut stdin = BufReader::new(stdin()).lines();
 
-    Swarm::listen_on(
-        &mut swarm,"/ip4/0.0.0.0/tcp/0".parse().expect("can get a local socket"),
-    ).expect("Swarm can be started");
+    Swarm::listen_on(&mut swarm, cli.listen.clone()).expect("Swarm can be started");
+
+    if let Some(addr) = &rendezvous_addr {
+        info!("Dialing rendezvous point at {}", addr);
+        Swarm::dial(&mut swarm, addr.clone()).expect("can dial rendezvous point");
+    }
 
     spawn(async move {
         sleep(Duration::from_secs(1)).await;
@@ -263,31 +385,45 @@ async fn main() {
     The first event emitter is the buffered reader which will give input lines from the user
     If we get one we create an EventType::Input with the line
     Then we listen to the response and init channel, creating their events respectively
-    If the events come in on the swarm itself this means they are events that are neither handled
-    by our Mdns behaviour nor FloodSub behaviour and we just log them. Mostly noise but helpful debugging tools
+    If the events come in on the swarm itself we check whether it's our connection to the
+    rendezvous point completing, in which case we register and ask it for peers; anything else
+    is neither handled by our Mdns, FloodSub, chain-sync, nor rendezvous behaviour and we just log
+    it. Mostly noise but helpful debugging tools
     With corresponding events created (or not), we go about handling them
     For our init event we call genesis() on our app to create the genesis block
-    If connected to nodes, trigger a LocalChainRequest to the last one in the list
+    (only when the on-disk store has no blocks yet; a restarted node keeps its stored chain)
+    If connected to nodes, send a directed LocalChainRequest to the last one in the list over the
+    chain-sync request-response protocol and await their reply there, rather than broadcasting it
     For simplicity we just ask one node and accept whatever they send us
-    If we get a LocalChainResponse Event then something was sent on the response channel
-    Broadcast the incoming JSON on the network to the correct FloodSub topic
-    For user input we have 3 commands:
+    For user input we have 5 commands:
     ls peers: lists peers
     ls chain: prints local blockchain
-    create block $data: create a new block with $data as the string content
+    ls registered: lists peers registered at the rendezvous point
+    create trans $sender $receiver $amount: queue a transaction into the local mempool
+    create block: mine a new block from whatever transactions are currently pending
     */
 
     loop {
         let evt = {
             select! {
                 line = stdin.next_line() => Some(p2p::EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
-                response = response_receiver.recv() => {
-                    Some(p2p::EventType::LocalChainResponse(response.expect("response exists")))
-                },
                 _init = init_receiver.recv() => {
                     Some(p2p::EventType::Init)
                 }
                 event = swarm.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, .. } = &event {
+                        if Some(*peer_id) == rendezvous_peer_id {
+                            swarm.behaviour_mut().register_with_rendezvous(*peer_id);
+                        }
+                    }
+                    // Rendezvous discovery only hands us addresses; dial them here so floodsub
+                    // actually has a transport connection to gossip over
+                    for addr in swarm.behaviour_mut().take_pending_dials() {
+                        info!("Dialing rendezvous-discovered peer at {}", addr);
+                        if let Err(e) = Swarm::dial(&mut swarm, addr.clone()) {
+                            warn!("Failed to dial discovered peer at {}: {:?}", addr, e);
+                        }
+                    }
                     info!("Unhandled Swarm Event: {:?}", event);
                     None
                 },
@@ -298,32 +434,23 @@ async fn main() {
             match event {
                 p2p::EventType::Init => {
                     let peers = p2p::get_list_peers(&swarm);
-                    swarm.behaviour_mut().app.genesis();
+                    if swarm.behaviour().app.store.is_empty() {
+                        swarm.behaviour_mut().app.genesis();
+                    }
 
                     info!("connected nodes: {}", peers.len());
-                    if !peers.is_empty() {
-                        let req = p2p::LocalChainRequest {
-                            from_peer_id: peers
-                            .iter()
-                            .last()
-                            .expect("at least one peer")
-                            .to_string(),
-                        };
-
-                        let json = serde_json::to_string(&req).expect("can jsonify request");
-                        swarm.behaviour_mut().floodsub.publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
+                    if let Some(last_peer) = peers.last() {
+                        let peer_id: PeerId = last_peer.parse().expect("discovered peers have valid peer ids");
+                        swarm.behaviour_mut().chain_sync.send_request(&peer_id, p2p::LocalChainRequest);
                     }
                 }
 
-                p2p::EventType::LocalChainResponse(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm.behaviour_mut().floodsub.publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
-                }
-
                 p2p::EventType::Input(line) => match line.as_str() {
                     "ls peers" => p2p::handle_print_peers(&swarm),
                     cmd if cmd.starts_with("ls chain") => p2p::handle_print_chain(&swarm),
+                    cmd if cmd.starts_with("ls registered") => p2p::handle_print_registered(&swarm),
                     cmd if cmd.starts_with("create block") => p2p::handle_create_block(cmd, &mut swarm),
+                    cmd if cmd.starts_with("create trans") => p2p::handle_create_transaction(cmd, &mut swarm),
                     _ => error!("Unknown command"),
                 },
             }
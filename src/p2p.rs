@@ -1,59 +1,182 @@
 // Imports
 use super::{App, Block};
+use crate::transaction::Transaction;
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
 use libp2p::{
     floodsub::{Floodsub, FloodsubEvent, Topic},
     identity,
     mdns::{Mdns, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, Swarm},
-    NetworkBehaviour, PeerId,
+    multiaddr::Protocol,
+    rendezvous,
+    request_response::{
+        ProtocolName, ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviourEventProcess, Swarm},
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use log::{error, info};
-use once_cell::sync::Lazy;
+use log::{error, info, warn};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use tokio::sync::mpsc;
+use std::path::Path;
+use std::{fs, io, iter};
 
 
 // ------------------- DATA STRUCTURES ------------------------
 
-// Key value pair and derived peer id for libp2p's intrinsics to identify clients on the network
-pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
-pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
-// Using FloodSub, a simple publish/subscribe protocol to communicate between nodes
-// These topics are channels to subscribe to. We can subscribe to chains and use them to send local blockchain to other nodes
-// and receive theirs. Similarly we can subscribe to blocks to send and receive new blocks
-pub static CHAIN_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("chains"));
-pub static BLOCK_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("blockchain"));
+// Key pair and derived peer id for libp2p's intrinsics to identify this node on the network. Set
+// once in main() after `load_or_generate_keypair` resolves the on-disk keyfile, so PEER_ID stays
+// stable across restarts instead of being re-rolled on every launch
+pub static KEYS: OnceCell<identity::Keypair> = OnceCell::new();
+pub static PEER_ID: OnceCell<PeerId> = OnceCell::new();
 
-// ChainResponse holds a list of blocks and receiver. This is expected if someone sends their local blockchain
+pub fn keys() -> &'static identity::Keypair {
+    KEYS.get().expect("keys are initialized in main() before use")
+}
+
+pub fn peer_id() -> PeerId {
+    *PEER_ID.get().expect("peer id is initialized in main() before use")
+}
+
+// Load this node's ed25519 keypair from `path`, generating and persisting a new one on first run
+// so the identity survives restarts
+pub fn load_or_generate_keypair(path: &Path) -> identity::Keypair {
+    if let Ok(bytes) = fs::read(path) {
+        return identity::Keypair::from_protobuf_encoding(&bytes)
+            .expect("can decode stored keyfile");
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).expect("can create keyfile directory");
+    }
+    fs::write(
+        path,
+        keypair.to_protobuf_encoding().expect("can encode keypair"),
+    )
+    .expect("can write keyfile");
+    info!("Generated new node identity, saved to {}", path.display());
+    keypair
+}
+
+// Using FloodSub, a simple publish/subscribe protocol to communicate between nodes.
+// Pending transactions are gossiped to everyone on this topic. Newly mined blocks are gossiped on
+// a topic named by the network's ChainSpec instead, since that can differ per chain
+pub static TRANSACTION_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("transactions"));
+
+// Namespace this chain's nodes register themselves under at a rendezvous point, so
+// discovery only surfaces peers belonging to the same network
+pub static RENDEZVOUS_NAMESPACE: Lazy<rendezvous::Namespace> =
+    Lazy::new(|| rendezvous::Namespace::from_static("rust-blockchain"));
+
+// Chain sync is a directed 1:1 exchange instead of a broadcast, so it runs over its
+// own request-response protocol rather than floodsub
+#[derive(Debug, Clone, Default)]
+pub struct ChainSyncCodec();
+
+#[derive(Debug, Clone)]
+pub struct ChainSyncProtocol();
+
+impl ProtocolName for ChainSyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/chainsync/1.0.0".as_bytes()
+    }
+}
+
+// ChainResponse holds a list of blocks and the chain_name the sender believes it belongs to, so
+// the requester can refuse chains from a differently-configured network. This is sent back as
+// the direct reply to a LocalChainRequest
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChainResponse {
     pub blockchain: Vec<Block>,
-    pub receiver: String
+    pub chain_name: String,
 }
 
-// This is what triggers the above interaction. Sending this with peer_id of another node will make them send us their chain back
+// This is what triggers the above interaction. Sending this to a peer makes them send their chain back
 #[derive(Debug, Serialize, Deserialize)]
-pub struct LocalChainRequest {
-    pub from_peer_id: String
+pub struct LocalChainRequest;
+
+#[async_trait]
+impl RequestResponseCodec for ChainSyncCodec {
+    type Protocol = ChainSyncProtocol;
+    type Request = LocalChainRequest;
+    type Response = ChainResponse;
+
+    async fn read_request<T>(&mut self, _: &ChainSyncProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = libp2p::core::upgrade::read_length_prefixed(io, 1_000_000).await?;
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &ChainSyncProtocol, io: &mut T) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = libp2p::core::upgrade::read_length_prefixed(io, 10_000_000).await?;
+        serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &ChainSyncProtocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&request)?;
+        libp2p::core::upgrade::write_length_prefixed(io, bytes).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &ChainSyncProtocol,
+        io: &mut T,
+        response: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let bytes = serde_json::to_vec(&response)?;
+        libp2p::core::upgrade::write_length_prefixed(io, bytes).await
+    }
 }
 
 // This is to handle incoming messages, lazy initialization, and keyboard input
 pub enum EventType {
-    LocalChainResponse(ChainResponse),
     Input(String),
     Init
 }
 
-// Holds the FloodSub instance and Mdns instance
+// Holds the FloodSub, Mdns, chain-sync, and (optional) rendezvous instances
 #[derive(NetworkBehaviour)]
+#[behaviour(event_process = true)]
 pub struct AppBehaviour {
     pub floodsub: Floodsub,
     pub mdns: Mdns,
+    pub chain_sync: RequestResponse<ChainSyncCodec>,
+    // mDNS only discovers peers on the local network segment; the rendezvous client lets a node
+    // additionally register with, and discover peers from, a known remote rendezvous point, and
+    // the server half lets a node act as that rendezvous point for others. Both are off unless
+    // requested on the command line, hence `Toggle`
+    pub rendezvous_client: Toggle<rendezvous::client::Behaviour>,
+    pub rendezvous_server: Toggle<rendezvous::server::Behaviour>,
+    #[behaviour(ignore)]
+    pub registered_peers: HashSet<PeerId>,
+    // Addresses of rendezvous-discovered peers waiting to be dialed. `inject_event` can't dial
+    // directly (it only has `&mut self`, not the `Swarm`), so the main loop drains this after
+    // each swarm event and dials on our behalf
     #[behaviour(ignore)]
-    pub response_sender: mpsc::UnboundedSender<ChainResponse>,
+    pub pending_dials: Vec<Multiaddr>,
+    // Named by the network's ChainSpec rather than a fixed constant, so two differently
+    // configured networks don't gossip blocks onto the same topic
     #[behaviour(ignore)]
-    pub init_sender: mpsc::UnboundedSender<bool>,
+    pub block_topic: Topic,
     #[behaviour(ignore)]
     pub app: App
 }
@@ -63,22 +186,50 @@ pub struct AppBehaviour {
 impl AppBehaviour {
     pub async fn new(
         app: App,
-        response_sender: mpsc::UnboundedSender<ChainResponse>,
-        init_sender: mpsc::UnboundedSender<bool>,
+        enable_rendezvous_client: bool,
+        enable_rendezvous_server: bool,
     ) -> Self {
+        let block_topic = Topic::new(app.chain_spec.block_topic.clone());
+
         let mut behaviour = Self {
             app,
-            floodsub: Floodsub::new(*PEER_ID),
+            floodsub: Floodsub::new(peer_id()),
             mdns: Mdns::new(Default::default()).await.expect("Can create mdns"),
-            response_sender,
-            init_sender
+            chain_sync: RequestResponse::new(
+                ChainSyncCodec(),
+                iter::once((ChainSyncProtocol(), ProtocolSupport::Full)),
+                Default::default(),
+            ),
+            rendezvous_client: enable_rendezvous_client
+                .then(|| rendezvous::client::Behaviour::new(keys().clone()))
+                .into(),
+            rendezvous_server: enable_rendezvous_server
+                .then(|| rendezvous::server::Behaviour::new(rendezvous::server::Config::default()))
+                .into(),
+            registered_peers: HashSet::new(),
+            pending_dials: Vec::new(),
+            block_topic,
         };
 
-        behaviour.floodsub.subscribe(CHAIN_TOPIC.clone());
-        behaviour.floodsub.subscribe(BLOCK_TOPIC.clone());
+        behaviour.floodsub.subscribe(behaviour.block_topic.clone());
+        behaviour.floodsub.subscribe(TRANSACTION_TOPIC.clone());
 
         behaviour
     }
+
+    // Register with, and ask for peers from, a rendezvous point once we're connected to it
+    pub fn register_with_rendezvous(&mut self, rendezvous_point: PeerId) {
+        if let Some(client) = self.rendezvous_client.as_mut() {
+            client.register(RENDEZVOUS_NAMESPACE.clone(), rendezvous_point, None);
+            client.discover(Some(RENDEZVOUS_NAMESPACE.clone()), None, None, rendezvous_point);
+        }
+    }
+
+    // Drain the addresses of rendezvous-discovered peers queued up for dialing, for the main
+    // loop to actually dial
+    pub fn take_pending_dials(&mut self) -> Vec<Multiaddr> {
+        std::mem::take(&mut self.pending_dials)
+    }
 }
 
 // Implement handlers for data incoming from other nodes
@@ -110,48 +261,139 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
 // Incoming event handler
 
 // For incoming events (FloodsubEvent::Message) we check whether the payload fits any of our expected data structures
-// If it's a ChainResponse, we got sent a local blockchain by another node
-// if it's a LocalChainRequest, check the peer id to see if they're the one we want the chain from and send them a JSON of our blockchain
 // If it's a Block, someone else mined a block and wants us to add it to local. Check validity and add
+// If it's a Transaction, queue it into our mempool so it can be picked up by the next mined block
 impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
     fn inject_event(&mut self, event: FloodsubEvent) {
         if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
-                if resp.receiver == PEER_ID.to_string() {
-                    info!("Response from {}:", msg.source);
-                    resp.blockchain.iter().for_each(|r| info!("{:?}", r));
+            if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
+                info!("Received new block from {}", msg.source);
+                self.app.try_add_block(block);
+            } else if let Ok(transaction) = serde_json::from_slice::<Transaction>(&msg.data) {
+                info!("Received new transaction from {}", msg.source);
+                self.app.mempool.push(transaction);
+            }
+        }
+    }
+}
+
+// Chain-sync events
+// Incoming event handler
 
-                    self.app.blockchain = self.app.choose_chain(self.app.blockchain.clone(), resp.blockchain);
+// A Request means a peer directly asked us for our chain; reply on the same channel with our
+// current blockchain. A Response means a peer we requested from sent their chain back; run it
+// through the longest-chain rule same as before
+impl NetworkBehaviourEventProcess<RequestResponseEvent<LocalChainRequest, ChainResponse>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<LocalChainRequest, ChainResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request { channel, .. } => {
+                    info!("Sending local chain to {}", peer);
+                    if let Err(e) = self.chain_sync.send_response(
+                        channel,
+                        ChainResponse {
+                            blockchain: self.app.blockchain.clone(),
+                            chain_name: self.app.chain_spec.chain_name.clone(),
+                        },
+                    ) {
+                        error!("Error sending chain sync response to {}: {:?}", peer, e);
+                    }
                 }
-            } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                info!("Sending local chain to {}", msg.source.to_string());
-                let peer_id = resp.from_peer_id;
-
-                if PEER_ID.to_string() == peer_id {
-                    if let Err(e) = self.response_sender.send(ChainResponse {
-                        blockchain: self.app.blockchain.clone(),
-                        receiver: msg.source.to_string()
-                    }) {
-                        error!("Error sending response via channel: {}", e);
+                RequestResponseMessage::Response { response, .. } => {
+                    if response.chain_name != self.app.chain_spec.chain_name {
+                        warn!(
+                            "Ignoring chain from {} - chain_name '{}' doesn't match ours '{}'",
+                            peer, response.chain_name, self.app.chain_spec.chain_name
+                        );
+                        return;
                     }
+
+                    info!("Response from {}:", peer);
+                    response.blockchain.iter().for_each(|r| info!("{:?}", r));
+
+                    let chosen =
+                        self.app.choose_chain(self.app.blockchain.clone(), response.blockchain);
+                    for block in &chosen {
+                        self.app.store.insert_block(block);
+                    }
+                    self.app.blockchain = chosen;
                 }
-            } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                info!("Received new block from {}", msg.source.to_string());
-                self.app.try_add_block(block);
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                error!("Chain sync request to {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                error!("Chain sync request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+// Rendezvous client events
+// Incoming event handler
+
+// When we discover registrations at the rendezvous point, feed the returned peers into
+// floodsub's partial view exactly like the mDNS discovery path does
+impl NetworkBehaviourEventProcess<rendezvous::client::Event> for AppBehaviour {
+    fn inject_event(&mut self, event: rendezvous::client::Event) {
+        match event {
+            rendezvous::client::Event::Registered { rendezvous_node, ttl, namespace } => {
+                info!("Registered with rendezvous point {} under '{}' (ttl {}s)", rendezvous_node, namespace, ttl);
+            }
+            rendezvous::client::Event::RegisterFailed(error) => {
+                error!("Failed to register with rendezvous point: {:?}", error);
+            }
+            rendezvous::client::Event::Discovered { registrations, .. } => {
+                for registration in registrations {
+                    let peer = registration.record.peer_id();
+                    self.registered_peers.insert(peer);
+                    self.floodsub.add_node_to_partial_view(peer);
+                    // Being in floodsub's partial view alone isn't enough - without an actual
+                    // transport connection to the peer there's nothing to gossip over, so queue
+                    // its addresses for the main loop to dial
+                    self.pending_dials
+                        .extend(registration.record.addresses().iter().cloned());
+                }
+            }
+            rendezvous::client::Event::DiscoverFailed { error, .. } => {
+                error!("Failed to discover peers via rendezvous: {:?}", error);
+            }
+            rendezvous::client::Event::Expired { peer } => {
+                self.registered_peers.remove(&peer);
+                self.floodsub.remove_node_from_partial_view(&peer);
             }
         }
     }
 }
 
+// Rendezvous server events
+// Incoming event handler, only relevant when this node was started with --rendezvous-server
+
+impl NetworkBehaviourEventProcess<rendezvous::server::Event> for AppBehaviour {
+    fn inject_event(&mut self, event: rendezvous::server::Event) {
+        info!("Rendezvous server event: {:?}", event);
+    }
+}
+
 // -------------------------- HELPER FUNCTIONS --------------------------
 
+// Pull the `/p2p/<peer id>` component out of a rendezvous point's multiaddr, which is
+// needed up front so we know who to register with once the dial connects
+pub fn extract_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    })
+}
+
+// Peers known either via local mDNS discovery or via a remote rendezvous point - used both for
+// `ls peers` and to pick who to ask for the chain at startup, so a node whose only peer is
+// rendezvous-discovered still gets a chain-sync request sent to it
 pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
     info!("Discovered Peers:");
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
-    }
+    let mut unique_peers: HashSet<PeerId> = swarm.behaviour().mdns.discovered_nodes().copied().collect();
+    unique_peers.extend(swarm.behaviour().registered_peers.iter().copied());
     unique_peers.iter().map(|p| p.to_string()).collect()
 }
 
@@ -167,24 +409,65 @@ pub fn handle_print_chain(swarm: &Swarm<AppBehaviour>) {
     info!("{}", pretty_json);
 }
 
+pub fn handle_print_registered(swarm: &Swarm<AppBehaviour>) {
+    info!("Peers registered at the rendezvous point:");
+    swarm.behaviour().registered_peers.iter().for_each(|p| info!("{}", p));
+}
+
 pub fn handle_create_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
-    if let Some(data) = cmd.strip_prefix("create b") {
+    if cmd.starts_with("create block") {
         let behaviour = swarm.behaviour_mut();
         let latest_block = behaviour
             .app
             .blockchain
             .last()
             .expect("there is at least one block");
+        let pending_transactions = std::mem::take(&mut behaviour.app.mempool);
         let block = Block::new(
             latest_block.block_id + 1,
             latest_block.hash.clone(),
-            data.to_owned(),
+            pending_transactions,
+            &behaviour.app.chain_spec,
+            &behaviour.app.blockchain,
         );
         let json = serde_json::to_string(&block).expect("can jsonify request");
+        behaviour.app.store.insert_block(&block);
         behaviour.app.blockchain.push(block);
         info!("broadcasting new block");
+        let block_topic = behaviour.block_topic.clone();
+        behaviour
+            .floodsub
+            .publish(block_topic, json.as_bytes());
+    }
+}
+
+// Parse "create trans $sender $receiver $amount" and queue it into the
+// local mempool, then gossip it to peers so it can be picked up by
+// whoever mines the next block
+pub fn handle_create_transaction(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    if let Some(rest) = cmd.strip_prefix("create trans") {
+        let parts: Vec<&str> = rest.split_whitespace().collect();
+        if parts.len() != 3 {
+            error!("usage: create trans <sender> <receiver> <amount>");
+            return;
+        }
+
+        let amount: f64 = match parts[2].parse() {
+            Ok(amount) => amount,
+            Err(_) => {
+                error!("amount must be a number");
+                return;
+            }
+        };
+
+        let transaction = Transaction::new(parts[0].to_owned(), parts[1].to_owned(), amount);
+        let json = serde_json::to_string(&transaction).expect("can jsonify transaction");
+
+        let behaviour = swarm.behaviour_mut();
+        behaviour.app.mempool.push(transaction);
+        info!("broadcasting new transaction");
         behaviour
             .floodsub
-            .publish(BLOCK_TOPIC.clone(), json.as_bytes());
+            .publish(TRANSACTION_TOPIC.clone(), json.as_bytes());
     }
 }
\ No newline at end of file
@@ -0,0 +1,103 @@
+// Imports
+use crate::Block;
+use log::info;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+// ----------------------------- STRUCTS ----------------------------------
+
+// Thin wrapper around a SQLite connection that durably persists the chain
+// to disk so a node doesn't have to re-sync from peers on every launch
+pub struct Storage {
+    conn: Connection,
+}
+
+// ----------------------------- IMPLEMENTATIONS ----------------------------------
+
+impl Storage {
+    pub fn new(path: &Path) -> Self {
+        let conn = Connection::open(path).expect("can open sqlite db");
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                block_id     INTEGER PRIMARY KEY,
+                hash         TEXT NOT NULL,
+                prev_hash    TEXT NOT NULL,
+                timestamp    INTEGER NOT NULL,
+                transactions TEXT NOT NULL,
+                merkle_root  TEXT NOT NULL,
+                nonce        INTEGER NOT NULL
+            )",
+            [],
+        )
+        .expect("can create blocks table");
+
+        Self { conn }
+    }
+
+    // Whether the on-disk store has no blocks yet, used to decide if the
+    // genesis step still needs to run
+    pub fn is_empty(&self) -> bool {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0))
+            .expect("can count blocks");
+        count == 0
+    }
+
+    // Write (or overwrite) a single block. Called whenever a block is
+    // accepted into the in-memory chain
+    pub fn insert_block(&self, block: &Block) {
+        let transactions =
+            serde_json::to_string(&block.transactions).expect("can jsonify transactions");
+
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO blocks
+                    (block_id, hash, prev_hash, timestamp, transactions, merkle_root, nonce)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    block.block_id as i64,
+                    block.hash,
+                    block.prev_hash,
+                    block.timestamp,
+                    transactions,
+                    block.merkle_root,
+                    block.nonce as i64,
+                ],
+            )
+            .expect("can insert block");
+    }
+
+    // Rehydrate the full chain from disk, ordered by block id, for use on
+    // App::new()
+    pub fn load_chain(&self) -> Vec<Block> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT block_id, hash, prev_hash, timestamp, transactions, merkle_root, nonce FROM blocks ORDER BY block_id ASC")
+            .expect("can prepare statement");
+
+        let blocks = stmt
+            .query_map([], |row| {
+                let transactions: String = row.get(4)?;
+                Ok(Block {
+                    block_id: row.get::<_, i64>(0)? as u64,
+                    hash: row.get(1)?,
+                    prev_hash: row.get(2)?,
+                    timestamp: row.get(3)?,
+                    transactions: serde_json::from_str(&transactions)
+                        .expect("can parse stored transactions"),
+                    merkle_root: row.get(5)?,
+                    nonce: row.get::<_, i64>(6)? as u64,
+                })
+            })
+            .expect("can query blocks")
+            // A row that fails to deserialize means the store is corrupted - fail loudly like
+            // every other I/O path here instead of silently truncating the rehydrated chain
+            .collect::<Result<Vec<Block>, _>>()
+            .expect("can load stored blocks");
+
+        info!("Loaded {} block(s) from disk store", blocks.len());
+        blocks
+    }
+}
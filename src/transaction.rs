@@ -0,0 +1,64 @@
+// Imports
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// ----------------------------- STRUCTS ----------------------------------
+
+// A single structured payload carried by a Block, in place of the raw
+// `data` string the chain used to hold
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    pub sender: String,
+    pub receiver: String,
+    pub amount: f64,
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+// ----------------------------- IMPLEMENTATIONS ----------------------------------
+
+impl Transaction {
+    pub fn new(sender: String, receiver: String, amount: f64) -> Self {
+        Self {
+            sender,
+            receiver,
+            amount,
+            timestamp: Utc::now().timestamp(),
+            signature: String::new(),
+        }
+    }
+}
+
+// ----------------------------- HELPERS ----------------------------------
+
+// Fold a block's transactions into a single Merkle root so the root can be
+// carried in the block hash instead of the raw transaction list
+pub fn merkle_root(transactions: &[Transaction]) -> String {
+    if transactions.is_empty() {
+        return hex::encode(Sha256::digest(b""));
+    }
+
+    let mut layer: Vec<String> = transactions
+        .iter()
+        .map(|transaction| {
+            let json = serde_json::to_string(transaction).expect("can jsonify transaction");
+            hex::encode(Sha256::digest(json.as_bytes()))
+        })
+        .collect();
+
+    while layer.len() > 1 {
+        let mut next_layer = Vec::with_capacity(layer.len().div_ceil(2));
+        for pair in layer.chunks(2) {
+            let combined = if pair.len() == 2 {
+                format!("{}{}", pair[0], pair[1])
+            } else {
+                format!("{}{}", pair[0], pair[0]) // odd node out, duplicate it
+            };
+            next_layer.push(hex::encode(Sha256::digest(combined.as_bytes())));
+        }
+        layer = next_layer;
+    }
+
+    layer.remove(0)
+}